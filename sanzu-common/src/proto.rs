@@ -8,8 +8,11 @@
 use anyhow::{Context, Result};
 use byteorder::ByteOrder;
 use byteorder::{LittleEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Cursor;
 use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const VERSION: &str = "0.1.1";
 
@@ -52,13 +55,13 @@ impl Tunnel {
     where
         T: prost::Message,
     {
-        // Send length, on 8 bytes
-        let mut buffer = vec![0u8; 8];
-        LittleEndian::write_u64(&mut buffer, req.encoded_len() as u64);
-        // Encode request
-        let mut req_buf = vec![];
-        req.encode(&mut req_buf).context("Cannot encode pkt")?;
-        buffer.append(&mut req_buf);
+        // Encode straight into a single buffer, leaving room for the 8-byte
+        // length prefix up front so the header can be filled in place
+        // afterwards instead of encoding separately and copying it in.
+        let mut buffer = BytesMut::with_capacity(8 + req.encoded_len());
+        buffer.put_bytes(0, 8);
+        LittleEndian::write_u64(&mut buffer[..8], req.encoded_len() as u64);
+        req.encode(&mut buffer).context("Cannot encode pkt")?;
         // Send request
         stream.write_all(&buffer).context("Cannot write pkt")?;
         Ok(())
@@ -96,6 +99,531 @@ impl Tunnel {
             }
         }
     }
+
+    /// Like [`Tunnel::recv`], but reuses `buf` across calls instead of
+    /// allocating a fresh header and body buffer every time.
+    pub fn recv_buffered<T>(stream: &mut dyn ReadWrite, buf: &mut RecvBuffer) -> Result<T>
+    where
+        T: prost::Message + Default,
+    {
+        let len_bytes = buf.take(stream, 8)?;
+        let len = LittleEndian::read_u64(&len_bytes) as usize;
+        if len > MAX_PACKET_LEN {
+            return Err(anyhow!("Packet too big!"));
+        }
+        let req_bytes = buf.take(stream, len)?;
+        match prost::Message::decode(req_bytes.clone()) {
+            Ok(pkt) => Ok(pkt),
+            Err(_) => {
+                // Try to parse as Error msg
+                let msg: tunnel::EventError =
+                    prost::Message::decode(req_bytes).context("Cannot decode pkt")?;
+                let mut err = anyhow!("Error from server");
+                for error in msg.errors {
+                    err = err.context(error);
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Reusable read buffer backing [`Tunnel::recv_buffered`].
+#[derive(Default)]
+pub struct RecvBuffer {
+    buf: BytesMut,
+}
+
+impl RecvBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ensure at least `want` bytes are buffered, reading more off `stream`
+    /// as needed, then split and return exactly `want` bytes from the front.
+    fn take(&mut self, stream: &mut dyn ReadWrite, want: usize) -> Result<Bytes> {
+        let mut chunk = [0u8; 8 * 1024];
+        while self.buf.len() < want {
+            let n = stream.read(&mut chunk).context("Cannot read pkt")?;
+            if n == 0 {
+                return Err(anyhow!("Connection closed while reading pkt"));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(self.buf.split_to(want).freeze())
+    }
+}
+
+/// Async counterpart of [`Tunnel`], using the same wire format.
+pub struct AsyncTunnel {}
+
+impl AsyncTunnel {
+    /// Send a serialized message through `stream`. See [`Tunnel::send`].
+    pub async fn send<S, T>(stream: &mut S, req: T) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+        T: prost::Message,
+    {
+        // Encode straight into a single buffer, leaving room for the 8-byte
+        // length prefix up front so the header can be filled in place
+        // afterwards instead of encoding separately and copying it in.
+        let mut buffer = BytesMut::with_capacity(8 + req.encoded_len());
+        buffer.put_bytes(0, 8);
+        LittleEndian::write_u64(&mut buffer[..8], req.encoded_len() as u64);
+        req.encode(&mut buffer).context("Cannot encode pkt")?;
+        // Send request
+        stream.write_all(&buffer).await.context("Cannot write pkt")?;
+        Ok(())
+    }
+
+    /// Receive a serialized message from `stream`. See [`Tunnel::recv`].
+    pub async fn recv<S, T>(stream: &mut S) -> Result<T>
+    where
+        S: AsyncRead + Unpin,
+        T: prost::Message + Default,
+    {
+        let mut buffer = vec![0u8; 0x8];
+        stream
+            .read_exact(&mut buffer)
+            .await
+            .context("Cannot read pkt")?;
+
+        let mut rdr = Cursor::new(buffer);
+        let len =
+            ReadBytesExt::read_u64::<LittleEndian>(&mut rdr).context("Cannot read len")? as usize;
+        if len > MAX_PACKET_LEN {
+            return Err(anyhow!("Packet too big!"));
+        }
+        let mut req_buffer = vec![0u8; len];
+        stream
+            .read_exact(&mut req_buffer)
+            .await
+            .context("Cannot read pkt")?;
+        match prost::Message::decode(req_buffer.as_slice()) {
+            Ok(pkt) => Ok(pkt),
+            Err(_) => {
+                // Try to parse as Error msg
+                let msg: tunnel::EventError =
+                    prost::Message::decode(req_buffer.as_slice()).context("Cannot decode pkt")?;
+                let mut err = anyhow!("Error from server");
+                for error in msg.errors {
+                    err = err.context(error);
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Chunks making up a streamed body are capped at this size on the wire, so a
+/// single slow/large `Read` never blocks the connection for long and streams
+/// can be interleaved at chunk granularity.
+const STREAM_CHUNK_LEN: usize = 16 * 1024;
+
+/// Top bit of the 4-byte chunk header: set while more chunks follow, cleared
+/// on the last chunk of the body.
+const STREAM_MORE_FLAG: u32 = 0x8000_0000;
+
+/// Lower 31 bits of the chunk header hold the chunk length. The all-ones
+/// value can never be produced by a real chunk (they're capped at
+/// `STREAM_CHUNK_LEN`), so it's reserved to mean "the stream was aborted;
+/// an `EventError` follows using the normal `Tunnel::send`/`recv` framing".
+const STREAM_LEN_MASK: u32 = 0x7FFF_FFFF;
+const STREAM_ABORT_HEADER: u32 = STREAM_LEN_MASK;
+
+/// Bit 30 of the chunk header, never set by a real chunk (same reasoning as
+/// [`STREAM_ABORT_HEADER`]): marks a teardown frame, which carries no
+/// payload and just frees a [`StreamId`] for reuse.
+const STREAM_TEARDOWN_HEADER: u32 = 0x4000_0000;
+
+/// Every chunk frame is addressed to a logical stream by a 2-byte id
+/// prefix, so a single [`ReadWrite`] connection can carry several
+/// independent bodies at once (see [`MultiplexedTunnel`]). Callers that only
+/// ever want one body at a time, like [`Tunnel::send_with_stream`], just
+/// address everything to [`DEFAULT_STREAM_ID`].
+pub type StreamId = u16;
+
+/// The stream id used by [`Tunnel::send_with_stream`]/[`Tunnel::recv_with_stream`],
+/// which don't need multiplexing.
+const DEFAULT_STREAM_ID: StreamId = 0;
+
+impl Tunnel {
+    /// Send `req` like [`Tunnel::send`], then stream `body` after it as a
+    /// sequence of chunk frames instead of buffering it whole.
+    ///
+    /// Each frame is a 2-byte stream id (always [`DEFAULT_STREAM_ID`] here),
+    /// a 4-byte little-endian header (see [`STREAM_MORE_FLAG`] and
+    /// [`STREAM_LEN_MASK`]), then that many bytes of payload. This
+    /// lets large framebuffer frames, clipboard blobs or file transfers be
+    /// sent without ever holding the whole body in memory.
+    pub fn send_with_stream<T>(
+        stream: &mut dyn ReadWrite,
+        req: T,
+        mut body: impl Read,
+    ) -> Result<()>
+    where
+        T: prost::Message,
+    {
+        Self::send(stream, req)?;
+        let mut buf = [0u8; STREAM_CHUNK_LEN];
+        loop {
+            let n = match body.read(&mut buf) {
+                Ok(n) => n,
+                Err(err) => {
+                    return Err(send_stream_abort(stream, anyhow::Error::new(err)));
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            write_stream_chunk(stream, DEFAULT_STREAM_ID, &buf[..n], true)?;
+        }
+        write_stream_chunk(stream, DEFAULT_STREAM_ID, &[], false)
+    }
+
+    /// Receive a message like [`Tunnel::recv`], then hand back a
+    /// [`BodyReader`] that lazily pulls the streamed body that follows it.
+    pub fn recv_with_stream<T>(stream: &mut dyn ReadWrite) -> Result<(T, BodyReader<'_>)>
+    where
+        T: prost::Message + Default,
+    {
+        let msg = Self::recv(stream)?;
+        Ok((
+            msg,
+            BodyReader {
+                stream,
+                buffered: vec![],
+                eof: false,
+            },
+        ))
+    }
+}
+
+/// Write one chunk frame: the 2-byte stream id, a 4-byte header, then `data`.
+fn write_stream_chunk(
+    stream: &mut dyn ReadWrite,
+    id: StreamId,
+    data: &[u8],
+    more: bool,
+) -> Result<()> {
+    let mut id_buf = [0u8; 2];
+    LittleEndian::write_u16(&mut id_buf, id);
+    stream
+        .write_all(&id_buf)
+        .context("Cannot write stream id")?;
+    let mut header = [0u8; 4];
+    let flag = if more { STREAM_MORE_FLAG } else { 0 };
+    LittleEndian::write_u32(&mut header, flag | data.len() as u32);
+    stream
+        .write_all(&header)
+        .context("Cannot write stream chunk header")?;
+    stream
+        .write_all(data)
+        .context("Cannot write stream chunk body")?;
+    Ok(())
+}
+
+/// Write a teardown frame for `id`, freeing it for reuse on the peer.
+fn write_stream_teardown(stream: &mut dyn ReadWrite, id: StreamId) -> Result<()> {
+    let mut id_buf = [0u8; 2];
+    LittleEndian::write_u16(&mut id_buf, id);
+    stream
+        .write_all(&id_buf)
+        .context("Cannot write stream id")?;
+    let mut header = [0u8; 4];
+    LittleEndian::write_u32(&mut header, STREAM_TEARDOWN_HEADER);
+    stream
+        .write_all(&header)
+        .context("Cannot write stream teardown header")
+}
+
+/// Abort a body being streamed: write the reserved abort header, then send
+/// `err` as an `EventError` using the normal message framing.
+fn send_stream_abort(stream: &mut dyn ReadWrite, err: anyhow::Error) -> anyhow::Error {
+    let mut id_buf = [0u8; 2];
+    LittleEndian::write_u16(&mut id_buf, DEFAULT_STREAM_ID);
+    let mut header = [0u8; 4];
+    LittleEndian::write_u32(&mut header, STREAM_ABORT_HEADER);
+    if let Err(write_err) = stream.write_all(&id_buf).and_then(|_| stream.write_all(&header)) {
+        return anyhow!("Error writing stream abort header: {:?}", write_err);
+    }
+    let err_msg = tunnel::EventError {
+        errors: vec![format!("{}", err)],
+    };
+    if let Err(send_err) = Tunnel::send(stream, err_msg) {
+        return anyhow!("Error sending stream abort: {:?}", send_err);
+    }
+    err
+}
+
+/// What [`read_stream_chunk`] found on the wire for one stream, besides a
+/// peer abort (surfaced directly as an `Err`). In a [`MultiplexedTunnel`],
+/// EOF is `Teardown`, not a `Data` chunk with `more` cleared — see
+/// [`MultiplexedTunnel::pump`]; a [`BodyReader`] ends its body at either.
+enum StreamFrame {
+    Data { data: Vec<u8>, more: bool },
+    Teardown,
+}
+
+/// Read one chunk frame written by [`write_stream_chunk`] or [`write_stream_teardown`].
+fn read_stream_chunk(stream: &mut dyn ReadWrite) -> Result<(StreamId, StreamFrame)> {
+    let mut id_buf = [0u8; 2];
+    stream
+        .read_exact(&mut id_buf)
+        .context("Cannot read stream id")?;
+    let id = LittleEndian::read_u16(&id_buf);
+
+    let mut header = [0u8; 4];
+    stream
+        .read_exact(&mut header)
+        .context("Cannot read stream chunk header")?;
+    let header = LittleEndian::read_u32(&header);
+    if header == STREAM_ABORT_HEADER {
+        let err_msg: tunnel::EventError =
+            Tunnel::recv(stream).context("Cannot read stream abort message")?;
+        let mut err = anyhow!("Stream {} aborted by peer", id);
+        for error in err_msg.errors {
+            err = err.context(error);
+        }
+        return Err(err);
+    }
+    if header == STREAM_TEARDOWN_HEADER {
+        return Ok((id, StreamFrame::Teardown));
+    }
+    let more = header & STREAM_MORE_FLAG != 0;
+    let len = (header & STREAM_LEN_MASK) as usize;
+    if len > STREAM_CHUNK_LEN {
+        return Err(anyhow!("Stream chunk too big!"));
+    }
+    let mut data = vec![0u8; len];
+    stream
+        .read_exact(&mut data)
+        .context("Cannot read stream chunk body")?;
+    Ok((id, StreamFrame::Data { data, more }))
+}
+
+/// Lazily pulls the body streamed after a message received through
+/// [`Tunnel::recv_with_stream`], never holding more than one chunk in memory.
+pub struct BodyReader<'a> {
+    stream: &'a mut dyn ReadWrite,
+    buffered: Vec<u8>,
+    eof: bool,
+}
+
+impl<'a> Read for BodyReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.buffered.is_empty() && !self.eof {
+            let (_id, frame) = read_stream_chunk(self.stream)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            match frame {
+                StreamFrame::Data { data, more } => {
+                    self.eof = !more;
+                    self.buffered = data;
+                }
+                StreamFrame::Teardown => {
+                    self.eof = true;
+                }
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.buffered.len());
+        buf[..n].copy_from_slice(&self.buffered[..n]);
+        self.buffered.drain(..n);
+        Ok(n)
+    }
+}
+
+/// One output stream tracked by [`MultiplexedTunnel`]: its scheduling
+/// weight, WDRR deficit counter, and bytes queued but not yet on the wire.
+struct OutStream {
+    priority: u16,
+    deficit: usize,
+    buf: VecDeque<u8>,
+}
+
+/// Multiplexes several prioritized streams over one [`ReadWrite`] connection
+/// using weighted deficit round-robin, so a bulk stream can't starve a
+/// latency-sensitive one.
+pub struct MultiplexedTunnel<'a> {
+    stream: &'a mut dyn ReadWrite,
+    out_streams: HashMap<StreamId, OutStream>,
+    out_order: VecDeque<StreamId>,
+    in_buffers: HashMap<StreamId, VecDeque<u8>>,
+    in_eof: HashSet<StreamId>,
+}
+
+/// Write handle for one stream opened with [`MultiplexedTunnel::open_stream`].
+/// Writes are buffered; they only reach the wire once
+/// [`MultiplexedTunnel::pump`] schedules this stream's turn, or when
+/// [`MultiplexedTunnel::close_stream`] flushes them.
+pub struct StreamWriter<'b, 'a> {
+    tunnel: &'b mut MultiplexedTunnel<'a>,
+    id: StreamId,
+}
+
+impl<'b, 'a> Write for StreamWriter<'b, 'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(stream) = self.tunnel.out_streams.get_mut(&self.id) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "stream was closed while a StreamWriter for it was alive",
+            ));
+        };
+        stream.buf.extend(buf.iter().copied());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Read handle for one stream, demultiplexed from the shared connection by
+/// [`MultiplexedTunnel::recv_stream`].
+pub struct StreamReader<'b, 'a> {
+    tunnel: &'b mut MultiplexedTunnel<'a>,
+    id: StreamId,
+}
+
+impl<'b, 'a> Read for StreamReader<'b, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(data) = self.tunnel.in_buffers.get_mut(&self.id) {
+                if !data.is_empty() {
+                    let n = std::cmp::min(buf.len(), data.len());
+                    for (slot, byte) in buf[..n].iter_mut().zip(data.drain(..n)) {
+                        *slot = byte;
+                    }
+                    return Ok(n);
+                }
+            }
+            if self.tunnel.in_eof.contains(&self.id) {
+                return Ok(0);
+            }
+            self.tunnel
+                .demux_once()
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        }
+    }
+}
+
+impl<'a> MultiplexedTunnel<'a> {
+    /// Wrap `stream` for multiplexed use. No streams are open yet.
+    pub fn new(stream: &'a mut dyn ReadWrite) -> Self {
+        MultiplexedTunnel {
+            stream,
+            out_streams: HashMap::new(),
+            out_order: VecDeque::new(),
+            in_buffers: HashMap::new(),
+            in_eof: HashSet::new(),
+        }
+    }
+
+    /// Open (or reopen, if previously closed) an outgoing stream `id` with
+    /// scheduling weight `priority`, returning a writer to queue bytes on it.
+    ///
+    /// `priority` is floored to 1: a weight of 0 would never be credited any
+    /// deficit by [`MultiplexedTunnel::pump`], so a stream opened with it
+    /// could buffer bytes forever without ever being scheduled out.
+    pub fn open_stream(&mut self, id: StreamId, priority: u16) -> StreamWriter<'_, 'a> {
+        let priority = priority.max(1);
+        self.out_streams.entry(id).or_insert_with(|| OutStream {
+            priority,
+            deficit: 0,
+            buf: VecDeque::new(),
+        });
+        if !self.out_order.contains(&id) {
+            self.out_order.push_back(id);
+        }
+        StreamWriter { tunnel: self, id }
+    }
+
+    /// Get a reader for incoming stream `id`, demultiplexing frames off the
+    /// shared connection as needed to fill it. Clears any EOF left over from
+    /// a previous incarnation of `id`, so a reused stream id reads as fresh.
+    pub fn recv_stream(&mut self, id: StreamId) -> StreamReader<'_, 'a> {
+        self.in_buffers.entry(id).or_insert_with(VecDeque::new);
+        self.in_eof.remove(&id);
+        StreamReader { tunnel: self, id }
+    }
+
+    /// Run one weighted-deficit-round-robin scheduling pass, writing out
+    /// every chunk that streams with enough accrued deficit can afford, in
+    /// `open_stream` order, until no stream can make further progress.
+    pub fn pump(&mut self) -> Result<()> {
+        for id in &self.out_order {
+            if let Some(s) = self.out_streams.get_mut(id) {
+                if !s.buf.is_empty() {
+                    s.deficit += s.priority as usize;
+                }
+            }
+        }
+        loop {
+            let mut progressed = false;
+            for id in self.out_order.clone() {
+                let Some(s) = self.out_streams.get_mut(&id) else {
+                    continue;
+                };
+                if s.buf.is_empty() {
+                    continue;
+                }
+                let chunk_len = std::cmp::min(s.buf.len(), STREAM_CHUNK_LEN);
+                if s.deficit < chunk_len {
+                    continue;
+                }
+                let chunk: Vec<u8> = s.buf.drain(..chunk_len).collect();
+                s.deficit -= chunk_len;
+                let more = !s.buf.is_empty();
+                write_stream_chunk(self.stream, id, &chunk, more)?;
+                progressed = true;
+            }
+            if !progressed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Close outgoing stream `id`: flush any remaining queued bytes directly
+    /// (bypassing the scheduler, since a stream being torn down no longer
+    /// needs to share the wire fairly), write a teardown frame, then free
+    /// the id for reuse.
+    pub fn close_stream(&mut self, id: StreamId) -> Result<()> {
+        if let Some(s) = self.out_streams.get_mut(&id) {
+            while !s.buf.is_empty() {
+                let chunk_len = std::cmp::min(s.buf.len(), STREAM_CHUNK_LEN);
+                let chunk: Vec<u8> = s.buf.drain(..chunk_len).collect();
+                let more = !s.buf.is_empty();
+                write_stream_chunk(self.stream, id, &chunk, more)?;
+            }
+        }
+        self.out_streams.remove(&id);
+        self.out_order.retain(|&x| x != id);
+        write_stream_teardown(self.stream, id)
+    }
+
+    /// Read and route exactly one frame off the connection to whichever
+    /// stream it's addressed to. Only a teardown frame ends a stream — a
+    /// data chunk's `more` bit is informational only, since `pump` clears
+    /// whatever happens to be queued on each round regardless of whether
+    /// the stream will be written to again. A chunk carrying data for `id`
+    /// also clears any EOF left from a previous incarnation of `id`, so
+    /// chunks that arrive for a reused id are picked back up even if the
+    /// reader hasn't called `recv_stream` again yet.
+    fn demux_once(&mut self) -> Result<()> {
+        let (id, frame) = read_stream_chunk(self.stream)?;
+        match frame {
+            StreamFrame::Teardown => {
+                self.in_eof.insert(id);
+            }
+            StreamFrame::Data { data, .. } => {
+                self.in_eof.remove(&id);
+                self.in_buffers
+                    .entry(id)
+                    .or_insert_with(VecDeque::new)
+                    .extend(data);
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Only send first chain error to avoid internal error leaks to client
@@ -119,8 +647,10 @@ pub fn send_server_err_event(sock: &mut dyn ReadWrite, err: anyhow::Error) -> an
 
 pub fn recv_client_msg_or_error(
     stream: &mut dyn ReadWrite,
+    buf: &mut RecvBuffer,
 ) -> Result<tunnel::message_client_ok::Msg> {
-    let msg: tunnel::ClientMsgOrErr = Tunnel::recv(stream).context("Error in recv pkt")?;
+    let msg: tunnel::ClientMsgOrErr =
+        Tunnel::recv_buffered(stream, buf).context("Error in recv pkt")?;
     match msg.msg {
         Some(tunnel::client_msg_or_err::Msg::Ok(msg_ok)) => {
             // Message is ok
@@ -161,9 +691,9 @@ macro_rules! send_server_msg_type {
 #[macro_export]
 macro_rules! recv_client_msg_type {
     (
-        $sock: expr, $name: ident
+        $sock: expr, $buf: expr, $name: ident
     ) => {{
-        match recv_client_msg_or_error($sock) {
+        match recv_client_msg_or_error($sock, $buf) {
             Err(err) => Err(err.context(anyhow!("Received error msg"))),
             Ok(msg) => {
                 if let tunnel::message_client_ok::Msg::$name(msg) = msg {
@@ -178,8 +708,10 @@ macro_rules! recv_client_msg_type {
 
 pub fn recv_server_msg_or_error(
     stream: &mut dyn ReadWrite,
+    buf: &mut RecvBuffer,
 ) -> Result<tunnel::message_server_ok::Msg> {
-    let msg: tunnel::ServerMsgOrErr = Tunnel::recv(stream).context("Error in recv pkt")?;
+    let msg: tunnel::ServerMsgOrErr =
+        Tunnel::recv_buffered(stream, buf).context("Error in recv pkt")?;
     match msg.msg {
         Some(tunnel::server_msg_or_err::Msg::Ok(msg_ok)) => {
             // Message is ok
@@ -224,9 +756,9 @@ pub fn send_client_err_event(sock: &mut dyn ReadWrite, err: anyhow::Error) -> an
 #[macro_export]
 macro_rules! recv_server_msg_type {
     (
-        $sock: expr, $name: ident
+        $sock: expr, $buf: expr, $name: ident
     ) => {{
-        match recv_server_msg_or_error($sock) {
+        match recv_server_msg_or_error($sock, $buf) {
             Err(err) => Err(err.context(anyhow!("Received error msg"))),
             Ok(msg) => {
                 if let tunnel::message_server_ok::Msg::$name(msg) = msg {
@@ -254,3 +786,340 @@ macro_rules! send_client_msg_type {
             .map_err(|err| anyhow!("Error in send: Peer has closed connection? ({:?})", err,))
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_header(more: bool, len: u32) -> [u8; 4] {
+        let mut header = [0u8; 4];
+        let flag = if more { STREAM_MORE_FLAG } else { 0 };
+        LittleEndian::write_u32(&mut header, flag | len);
+        header
+    }
+
+    #[test]
+    fn stream_chunk_roundtrip() {
+        let mut wire = Cursor::new(Vec::new());
+        write_stream_chunk(&mut wire, 7, b"hello", true).unwrap();
+        write_stream_chunk(&mut wire, 7, b"world", false).unwrap();
+        wire.set_position(0);
+
+        let (id, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert_eq!(id, 7);
+        assert!(matches!(frame, StreamFrame::Data { ref data, more } if data == b"hello" && more));
+
+        let (id, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert_eq!(id, 7);
+        assert!(matches!(frame, StreamFrame::Data { ref data, more } if data == b"world" && !more));
+    }
+
+    #[test]
+    fn oversized_chunk_is_rejected_before_reading_its_body() {
+        let mut wire = Cursor::new(Vec::new());
+        let mut id_buf = [0u8; 2];
+        LittleEndian::write_u16(&mut id_buf, 1);
+        wire.write_all(&id_buf).unwrap();
+        wire.write_all(&raw_header(false, STREAM_CHUNK_LEN as u32 + 1))
+            .unwrap();
+        // No payload bytes follow: the length check must reject this frame
+        // before it ever tries to `read_exact` the oversized body.
+        wire.set_position(0);
+        let err = read_stream_chunk(&mut wire).unwrap_err();
+        assert!(err.to_string().contains("too big"));
+    }
+
+    #[test]
+    fn teardown_frame_is_distinct_from_a_final_data_chunk() {
+        let mut wire = Cursor::new(Vec::new());
+        write_stream_teardown(&mut wire, 3).unwrap();
+        wire.set_position(0);
+        let (id, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert_eq!(id, 3);
+        assert!(matches!(frame, StreamFrame::Teardown));
+    }
+
+    #[test]
+    fn aborted_stream_surfaces_the_peer_error() {
+        let mut wire = Cursor::new(Vec::new());
+        send_stream_abort(&mut wire, anyhow!("boom"));
+        wire.set_position(0);
+        let err = read_stream_chunk(&mut wire).unwrap_err();
+        assert!(format!("{:?}", err).contains("boom"));
+    }
+
+    #[test]
+    fn send_with_stream_roundtrips_an_empty_body() {
+        let mut wire = Cursor::new(Vec::new());
+        let req = tunnel::EventError {
+            errors: vec!["ctrl".into()],
+        };
+        Tunnel::send_with_stream(&mut wire, req.clone(), std::io::empty()).unwrap();
+        wire.set_position(0);
+
+        let (msg, mut body): (tunnel::EventError, _) = Tunnel::recv_with_stream(&mut wire).unwrap();
+        assert_eq!(msg, req);
+        let mut buf = Vec::new();
+        body.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn send_with_stream_caps_chunks_at_stream_chunk_len_and_ends_with_an_empty_frame() {
+        let mut wire = Cursor::new(Vec::new());
+        let body = vec![7u8; STREAM_CHUNK_LEN * 2 + 10];
+        Tunnel::send_with_stream(&mut wire, tunnel::EventError::default(), body.as_slice()).unwrap();
+        wire.set_position(0);
+
+        let _: tunnel::EventError = Tunnel::recv(&mut wire).unwrap();
+
+        let (id, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert_eq!(id, DEFAULT_STREAM_ID);
+        assert!(
+            matches!(frame, StreamFrame::Data { ref data, more } if data.len() == STREAM_CHUNK_LEN && more)
+        );
+
+        let (_, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert!(
+            matches!(frame, StreamFrame::Data { ref data, more } if data.len() == STREAM_CHUNK_LEN && more)
+        );
+
+        let (_, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert!(matches!(frame, StreamFrame::Data { ref data, more } if data.len() == 10 && more));
+
+        let (_, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert!(matches!(frame, StreamFrame::Data { ref data, more } if data.is_empty() && !more));
+    }
+
+    #[tokio::test]
+    async fn async_tunnel_send_matches_tunnel_send_on_the_wire() {
+        let req = tunnel::EventError {
+            errors: vec!["ping".into()],
+        };
+
+        let mut expected = Cursor::new(Vec::new());
+        Tunnel::send(&mut expected, req.clone()).unwrap();
+        let expected = expected.into_inner();
+
+        let (mut tx, mut rx) = tokio::io::duplex(expected.len());
+        AsyncTunnel::send(&mut tx, req).await.unwrap();
+        let mut got = vec![0u8; expected.len()];
+        rx.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, expected);
+    }
+
+    #[tokio::test]
+    async fn async_tunnel_recv_roundtrips_with_async_tunnel_send() {
+        let (mut tx, mut rx) = tokio::io::duplex(256);
+        let req = tunnel::EventError {
+            errors: vec!["pong".into()],
+        };
+        AsyncTunnel::send(&mut tx, req.clone()).await.unwrap();
+        let got: tunnel::EventError = AsyncTunnel::recv(&mut rx).await.unwrap();
+        assert_eq!(got, req);
+    }
+
+    #[tokio::test]
+    async fn async_tunnel_recv_falls_back_to_event_error_on_decode_failure() {
+        let err_msg = tunnel::EventError {
+            errors: vec!["boom".into()],
+        };
+        let mut staged = Cursor::new(Vec::new());
+        Tunnel::send(&mut staged, err_msg).unwrap();
+
+        let (mut tx, mut rx) = tokio::io::duplex(256);
+        tx.write_all(&staged.into_inner()).await.unwrap();
+
+        let result: Result<tunnel::ClientMsgOrErr> = AsyncTunnel::recv(&mut rx).await;
+        let err = result.unwrap_err();
+        assert!(format!("{:?}", err).contains("boom"));
+    }
+
+    /// Wraps a `Read`/`Write` and caps every `read` call at `max_read` bytes,
+    /// regardless of the caller's buffer size, to simulate a stream that
+    /// delivers a message across several short reads.
+    struct Stutter<T> {
+        inner: T,
+        max_read: usize,
+    }
+
+    impl<T: Read> Read for Stutter<T> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.max_read);
+            self.inner.read(&mut buf[..n])
+        }
+    }
+
+    impl<T: Write> Write for Stutter<T> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn recv_buffer_reads_two_messages_packed_into_one_underlying_read() {
+        let mut wire = Cursor::new(Vec::new());
+        let a = tunnel::EventError {
+            errors: vec!["a".into()],
+        };
+        let b = tunnel::EventError {
+            errors: vec!["b".into()],
+        };
+        Tunnel::send(&mut wire, a.clone()).unwrap();
+        Tunnel::send(&mut wire, b.clone()).unwrap();
+        wire.set_position(0);
+
+        let mut buf = RecvBuffer::new();
+        let got_a: tunnel::EventError = Tunnel::recv_buffered(&mut wire, &mut buf).unwrap();
+        let got_b: tunnel::EventError = Tunnel::recv_buffered(&mut wire, &mut buf).unwrap();
+        assert_eq!(got_a, a);
+        assert_eq!(got_b, b);
+    }
+
+    #[test]
+    fn recv_buffer_reassembles_a_message_delivered_across_short_reads() {
+        let mut wire = Cursor::new(Vec::new());
+        let msg = tunnel::EventError {
+            errors: vec!["chunked".into()],
+        };
+        Tunnel::send(&mut wire, msg.clone()).unwrap();
+        let mut stutter = Stutter {
+            inner: wire,
+            max_read: 3,
+        };
+
+        let mut buf = RecvBuffer::new();
+        let got: tunnel::EventError = Tunnel::recv_buffered(&mut stutter, &mut buf).unwrap();
+        assert_eq!(got, msg);
+    }
+
+    #[test]
+    fn recv_with_stream_surfaces_a_peer_abort_through_the_body_reader() {
+        let mut wire = Cursor::new(Vec::new());
+        Tunnel::send(&mut wire, tunnel::EventError::default()).unwrap();
+        send_stream_abort(&mut wire, anyhow!("disk full"));
+        wire.set_position(0);
+
+        let (_msg, mut body): (tunnel::EventError, _) = Tunnel::recv_with_stream(&mut wire).unwrap();
+        let mut buf = [0u8; 1];
+        let err = body.read(&mut buf).unwrap_err();
+        assert!(err.to_string().contains("disk full"));
+    }
+
+    #[test]
+    fn a_more_false_data_chunk_does_not_end_a_multiplexed_stream() {
+        // `pump` writes `more = false` whenever it happens to drain a
+        // stream's entire queue, even though the stream is still open and
+        // more will be written to it later (e.g. a bursty input stream).
+        // Only a teardown frame may end the stream.
+        let mut wire = Cursor::new(Vec::new());
+        write_stream_chunk(&mut wire, 1, b"hello", false).unwrap();
+        write_stream_chunk(&mut wire, 1, b"world", false).unwrap();
+        write_stream_teardown(&mut wire, 1).unwrap();
+        wire.set_position(0);
+
+        let mut tunnel = MultiplexedTunnel::new(&mut wire);
+
+        let mut first = [0u8; 5];
+        tunnel.recv_stream(1).read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"hello");
+
+        let mut second = [0u8; 5];
+        tunnel.recv_stream(1).read_exact(&mut second).unwrap();
+        assert_eq!(&second, b"world");
+
+        let mut buf = [0u8; 1];
+        assert_eq!(tunnel.recv_stream(1).read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn stream_id_can_be_reused_after_teardown() {
+        let mut wire = Cursor::new(Vec::new());
+        write_stream_teardown(&mut wire, 9).unwrap();
+        write_stream_chunk(&mut wire, 9, b"again", false).unwrap();
+        wire.set_position(0);
+
+        let mut tunnel = MultiplexedTunnel::new(&mut wire);
+        let mut buf = [0u8; 1];
+        assert_eq!(tunnel.recv_stream(9).read(&mut buf).unwrap(), 0);
+
+        // Asking for the stream again clears the stale EOF, so the reused
+        // id's fresh data is read normally instead of staying stuck at EOF.
+        let mut second = [0u8; 5];
+        tunnel.recv_stream(9).read_exact(&mut second).unwrap();
+        assert_eq!(&second, b"again");
+    }
+
+    #[test]
+    fn writing_to_a_closed_stream_errors_instead_of_panicking() {
+        let mut wire = Cursor::new(Vec::new());
+        let mut tunnel = MultiplexedTunnel::new(&mut wire);
+        tunnel.open_stream(1, 10);
+        tunnel.close_stream(1).unwrap();
+
+        let mut writer = StreamWriter {
+            tunnel: &mut tunnel,
+            id: 1,
+        };
+        assert!(writer.write_all(b"late").is_err());
+    }
+
+    #[test]
+    fn zero_priority_is_floored_so_pump_still_schedules_it() {
+        let mut wire = Cursor::new(Vec::new());
+        let mut tunnel = MultiplexedTunnel::new(&mut wire);
+        tunnel.open_stream(1, 0).write_all(b"ping").unwrap();
+        // A weight of 1 credits one byte of deficit per pass, so the 4-byte
+        // chunk goes out on the fourth pass.
+        for _ in 0..4 {
+            tunnel.pump().unwrap();
+        }
+        drop(tunnel);
+
+        wire.set_position(0);
+        let (id, frame) = read_stream_chunk(&mut wire).unwrap();
+        assert_eq!(id, 1);
+        assert!(matches!(frame, StreamFrame::Data { .. }));
+    }
+
+    #[test]
+    fn high_priority_stream_is_interleaved_with_a_bulk_stream() {
+        let mut wire = Cursor::new(Vec::new());
+        let mut tunnel = MultiplexedTunnel::new(&mut wire);
+        for _ in 0..4 {
+            // Bulk: exactly one chunk's worth of deficit per round, so it
+            // drains in lockstep instead of hogging several rounds' worth.
+            tunnel
+                .open_stream(1, STREAM_CHUNK_LEN as u16)
+                .write_all(&vec![0u8; STREAM_CHUNK_LEN])
+                .unwrap();
+            // High-priority: a handful of bytes, comfortably affordable
+            // every round.
+            tunnel.open_stream(2, 1000).write_all(b"click").unwrap();
+            tunnel.pump().unwrap();
+        }
+        drop(tunnel);
+
+        let total_len = wire.get_ref().len();
+        wire.set_position(0);
+        let mut ids = vec![];
+        while (wire.position() as usize) < total_len {
+            let (id, frame) = read_stream_chunk(&mut wire).unwrap();
+            if let StreamFrame::Data { .. } = frame {
+                ids.push(id);
+            }
+        }
+
+        assert!(ids.contains(&2), "high-priority stream must get a turn");
+        let first_control = ids.iter().position(|&id| id == 2).unwrap();
+        let last_bulk = ids.iter().rposition(|&id| id == 1).unwrap();
+        assert!(
+            first_control < last_bulk,
+            "high-priority chunks must be interleaved with the bulk stream, not stuck behind all of it: {:?}",
+            ids
+        );
+    }
+}